@@ -0,0 +1,44 @@
+//! Live V4L2 capture source. Instead of playing back a pre-generated work
+//! file, `--capture /dev/videoN` negotiates a streaming format on a real
+//! camera and feeds its dequeued buffers into the same `Buffered`/
+//! `read_buffer` machinery the look-ahead buffer model already drains, so
+//! rebuffering accounting works identically against live hardware.
+
+use crossbeam_channel::{bounded, Sender};
+use linuxvideo::{Device, format::PixFormat, format::PixelFormat};
+use std::thread;
+use Buffered;
+
+/// Open `path`, negotiate an MJPG capture stream, and start a background
+/// thread dequeuing buffers into a bounded channel exactly like `read_file`
+/// does for work files. Returns the `Buffered` source and the frame size the
+/// device negotiated, which the caller should use instead of `--size`.
+pub fn open(path: &str) -> (Buffered, usize) {
+  let device  = Device::open(path).unwrap();
+  let capture = device.video_capture(PixFormat::new(0, 0, PixelFormat::MJPG)).unwrap();
+  let frame_sz = capture.format().size_image() as usize;
+
+  let mut stream       = capture.into_stream(4).unwrap();
+  let (tx, rx)         = bounded(8);
+
+  thread::spawn(move || { capture_loop(&mut stream, tx) });
+
+  (Buffered { local: 0, chan: rx }, frame_sz)
+}
+
+/// Dequeue buffers from the device and report their size on `tx`, the same
+/// contract `read_file` uses: a `Receiver<usize>` of chunk sizes. Returns
+/// (ending the capture) once the device stops producing buffers.
+fn capture_loop<S>(stream: &mut S, tx: Sender<usize>)
+  where S: Iterator<Item = ::linuxvideo::Result<::linuxvideo::buffer::Buffer>> {
+  loop {
+    match stream.next() {
+      Some(Ok(buf)) => {
+        if tx.send(buf.bytes_used() as usize).is_err() {
+          return;
+        }
+      }
+      _ => { return; }
+    }
+  }
+}
@@ -0,0 +1,140 @@
+//! Coordinator/worker protocol used to align `vio` runs across multiple
+//! machines. One node listens (`--listen PORT`) and the rest connect to it
+//! (`--coordinator HOST:PORT`); once a fixed accept window has passed the
+//! coordinator broadcasts a single GO frame to whoever connected so every host's `play` threads start their
+//! `SteadyTime` clocks within a bounded window, and at the end each worker
+//! reports its `(total, stalls)` back for an aggregated summary.
+//!
+//! Messages are framed as a 4-byte big-endian length prefix followed by that
+//! many bytes of payload (tag byte + body), modeled on the small
+//! custom-protocol TCP server rustc's remote-test-server uses to coordinate
+//! cross-machine test runs.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const MSG_GO:     u8 = 1;
+const MSG_RESULT: u8 = 2;
+
+/// Read one length-prefixed frame from `stream`, returning the tag byte and
+/// the remaining payload, or `None` if the peer closed the connection.
+fn read_frame(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+  let mut lenbuf = [0u8; 4];
+  if stream.read_exact(&mut lenbuf).is_err() {
+    return None;
+  }
+  let len = ((lenbuf[0] as u32) << 24) | ((lenbuf[1] as u32) << 16) |
+            ((lenbuf[2] as u32) << 8)  |  (lenbuf[3] as u32);
+  let mut body = vec![0u8; len as usize];
+  if body.len() > 0 && stream.read_exact(&mut body).is_err() {
+    return None;
+  }
+  if body.len() == 0 {
+    None
+  } else {
+    let tag = body[0];
+    Some((tag, body[1..].to_vec()))
+  }
+}
+
+/// Write a single length-prefixed frame with the given tag byte and payload.
+fn write_frame(stream: &mut TcpStream, tag: u8, payload: &[u8]) {
+  let mut body = Vec::with_capacity(1 + payload.len());
+  body.push(tag);
+  body.extend_from_slice(payload);
+  let len = body.len() as u32;
+  let lenbuf = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+  stream.write_all(&lenbuf).unwrap();
+  stream.write_all(&body).unwrap();
+}
+
+fn encode_result(total: i32, stalls: i32) -> Vec<u8> {
+  let t = total as u32;
+  let s = stalls as u32;
+  vec![
+    (t >> 24) as u8, (t >> 16) as u8, (t >> 8) as u8, t as u8,
+    (s >> 24) as u8, (s >> 16) as u8, (s >> 8) as u8, s as u8,
+  ]
+}
+
+fn decode_result(payload: &[u8]) -> (i32, i32) {
+  let t = ((payload[0] as u32) << 24) | ((payload[1] as u32) << 16) |
+          ((payload[2] as u32) << 8)  |  (payload[3] as u32);
+  let s = ((payload[4] as u32) << 24) | ((payload[5] as u32) << 16) |
+          ((payload[6] as u32) << 8)  |  (payload[7] as u32);
+  (t as i32, s as i32)
+}
+
+/// Accept worker connections on `port` for `accept_window`, then broadcast a
+/// single GO frame to everyone who showed up and block until each sends back
+/// its `(total, stalls)` result. Prints an aggregated cross-machine summary.
+///
+/// One accept thread runs per incoming connection, the same pattern rustc's
+/// remote-test-server uses so a single slow client can't block anyone else
+/// from connecting during the accept window.
+pub fn run_coordinator(port: u16, accept_window: Duration) {
+  let listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
+  let streams: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
+
+  {
+    let streams  = streams.clone();
+    let listener = listener.try_clone().unwrap();
+    thread::spawn(move || {
+      for conn in listener.incoming() {
+        match conn {
+          Ok(stream) => {
+            let streams = streams.clone();
+            thread::spawn(move || { streams.lock().unwrap().push(stream); });
+          }
+          Err(_) => { return; }
+        }
+      }
+    });
+  }
+
+  thread::sleep(accept_window);
+
+  let mut workers = streams.lock().unwrap();
+  println!("Broadcasting GO to {} worker(s)", workers.len());
+  for stream in workers.iter_mut() {
+    write_frame(stream, MSG_GO, &[]);
+  }
+
+  let mut total  = 0i32;
+  let mut stalls = 0i32;
+  for stream in workers.iter_mut() {
+    match read_frame(stream) {
+      Some((MSG_RESULT, payload)) => {
+        let (t, s) = decode_result(&payload);
+        total  += t;
+        stalls += s;
+      }
+      _ => { println!("a worker disconnected before reporting a result"); }
+    }
+  }
+
+  let percent = 100.0 * (stalls as f32) / (total as f32);
+  println!("Cross-machine summary: {} frames, {} rebuffers ({}%)", total, stalls, percent);
+}
+
+/// Connect to the coordinator at `addr` ("host:port") and block until the GO
+/// frame arrives, returning the open connection so the caller can report its
+/// result back once the run finishes.
+pub fn wait_for_go(addr: &str) -> TcpStream {
+  let mut stream = TcpStream::connect(addr).unwrap();
+  loop {
+    match read_frame(&mut stream) {
+      Some((MSG_GO, _)) => { return stream; }
+      Some(_)           => { continue; }
+      None              => { panic!("coordinator closed the connection before sending GO"); }
+    }
+  }
+}
+
+/// Send this worker's aggregated `(total, stalls)` back to the coordinator.
+pub fn send_result(mut stream: TcpStream, total: i32, stalls: i32) {
+  write_frame(&mut stream, MSG_RESULT, &encode_result(total, stalls));
+}
@@ -2,7 +2,18 @@ extern crate rand;
 extern crate time;
 extern crate getopts;
 extern crate mersenne_twister;
+#[cfg(feature = "mmap")]
+extern crate memmap;
+extern crate linuxvideo;
+extern crate crossbeam_channel;
 
+mod coordinator;
+#[cfg(feature = "mmap")]
+mod mmap_source;
+mod capture_source;
+mod stream_buffer;
+
+use crossbeam_channel::{bounded, Receiver, Sender, RecvTimeoutError};
 use getopts::Options;
 use mersenne_twister::MersenneTwister;
 use rand::{Rng, SeedableRng, random};
@@ -10,49 +21,104 @@ use std::env;
 use std::fs::{OpenOptions, File, metadata};
 use std::io::{Read, Write};
 use std::iter::repeat;
+use std::net::TcpStream;
 use std::process::exit;
 use std::str::FromStr;
 use std::string::String;
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::thread;
 use std::thread::sleep_ms;
+use std::time::Duration as StdDuration;
 use time::{Duration, SteadyTime};
 
 #[derive(Clone, Debug)]
 struct Config {
-  threads    :i32,
-  framerate  :f32,
-  framesize  :usize,
-  timelimit  :Duration,
-  workdir    :String,
-  hostname   :String,
+  threads     :i32,
+  framerate   :f32,
+  framesize   :usize,
+  timelimit   :Duration,
+  workdir     :String,
+  hostname    :String,
+  coordinator :Option<String>,
+  listen      :Option<u16>,
+  mmap        :bool,
+  capture     :Option<String>,
+  lookahead   :f64,
+  maxstall    :i32,
+  statsfile   :Option<String>,
 }
 
+/// How long the coordinator waits for workers to connect before broadcasting
+/// GO. Generous enough for a handful of machines to dial in over a LAN.
+const ACCEPT_WINDOW_SECS: u64 = 5;
+
+/// Default look-ahead buffer target, in seconds of video.
+const DEFAULT_LOOKAHEAD_SECS: f64 = 15.0;
+
+/// Default watchdog threshold: abort the stream once more than this many
+/// consecutive frames find the playback buffer empty.
+const DEFAULT_MAX_STALL_CHUNKS: i32 = 30;
+
 fn main() {
   let config  = opts();
+
+  if let Some(port) = config.listen {
+    coordinator::run_coordinator(port, StdDuration::from_secs(ACCEPT_WINDOW_SECS));
+    return;
+  }
+
   let mut ts  = Vec::new();
-  let thcount = config.threads;
   let mut all = true;
 
-  for i in 0..thcount {
-    all = verify_workfile(&config, i) && all;
-  }
-  if !all {
-    println!("Created work files. quitting.");
-    return;
+  // A capture device can only be streamed by one thread at a time; most
+  // V4L2 drivers reject a second concurrent streaming instance on the same
+  // node, so `--capture` ignores `--threads` instead of letting that panic.
+  let thcount = if config.capture.is_some() {
+    if config.threads > 1 {
+      println!("--capture only supports a single thread; ignoring --threads {}", config.threads);
+    }
+    1
+  } else {
+    config.threads
+  };
+
+  if config.capture.is_none() {
+    for i in 0..thcount {
+      all = verify_workfile(&config, i) && all;
+    }
+    if !all {
+      println!("Created work files. quitting.");
+      return;
+    }
   }
 
+  // Wait for the coordinator's GO only once this node is actually ready to
+  // start playing; otherwise a worker that still needs to create its work
+  // files would get GO and then spend unbounded time writing them, blowing
+  // past the bounded window the coordinator exists to enforce.
+  let goconn: Option<TcpStream> = config.coordinator.as_ref()
+    .map(|addr| coordinator::wait_for_go(addr));
+
   for i in 0..thcount {
     let conf = config.clone();
-    ts.push(thread::spawn(move || {play(&conf, i);}));
+    ts.push(thread::spawn(move || {play(&conf, i)}));
   }
 
+  let mut total = 0;
+  let mut stalls = 0;
   loop {
     match ts.pop() {
-      None => return,
-      Some(handle) => {handle.join(); ()},
+      None => break,
+      Some(handle) => {
+        let (t, s) = handle.join().unwrap();
+        total += t;
+        stalls += s;
+      }
     }
   }
+
+  if let Some(stream) = goconn {
+    coordinator::send_result(stream, total, stalls);
+  }
 }
 
 /// Parse argv options into a configuration object. This will panic if the
@@ -68,6 +134,13 @@ fn opts() -> Config {
   opts.optopt("r", "rate", "set code frame rate", "RATE");
   opts.optopt("s", "size", "set code frame size", "SIZE");
   opts.optopt("l", "limit", "set time limit", "SECONDS");
+  opts.optopt("", "coordinator", "connect to a coordinator and wait for GO", "HOST:PORT");
+  opts.optopt("", "listen", "act as coordinator, listening on this port", "PORT");
+  opts.optflag("", "mmap", "map the work file instead of reading it through a thread/channel pipeline");
+  opts.optopt("", "capture", "capture from a V4L2 device instead of a work file", "/dev/videoN");
+  opts.optopt("", "lookahead", "seconds of look-ahead buffer to target", "SECONDS");
+  opts.optopt("", "max-stall-chunks", "abort once more than this many consecutive frames find an empty buffer", "N");
+  opts.optopt("", "stats-file", "write per-second latency/occupancy stats here (.json for JSON, else CSV)", "PATH");
   let matches = match opts.parse(&args[1..]) {
     Ok(m) => { m }
     Err(f) => { panic!(f.to_string()) }
@@ -103,13 +176,40 @@ fn opts() -> Config {
     None      => {8*60}
     Some(s) => {FromStr::from_str(&s).unwrap()} };
 
+  let coordinator = matches.opt_str("coordinator");
+
+  let listen = match matches.opt_str("listen") {
+    None      => {None}
+    Some(p) => {Some(FromStr::from_str(&p).unwrap())} };
+
+  let mmap = matches.opt_present("mmap");
+
+  let capture = matches.opt_str("capture");
+
+  let lookahead = match matches.opt_str("lookahead") {
+    None      => {DEFAULT_LOOKAHEAD_SECS}
+    Some(l) => {FromStr::from_str(&l).unwrap()} };
+
+  let maxstall = match matches.opt_str("max-stall-chunks") {
+    None      => {DEFAULT_MAX_STALL_CHUNKS}
+    Some(m) => {FromStr::from_str(&m).unwrap()} };
+
+  let statsfile = matches.opt_str("stats-file");
+
   Config {
-    threads:    threads,
-    framerate:  rate,
-    framesize:  size,
-    timelimit:  Duration::seconds(sec),
-    workdir:    dir,
-    hostname:   host,
+    threads:     threads,
+    framerate:   rate,
+    framesize:   size,
+    timelimit:   Duration::seconds(sec),
+    workdir:     dir,
+    hostname:    host,
+    coordinator: coordinator,
+    listen:      listen,
+    mmap:        mmap,
+    capture:     capture,
+    lookahead:   lookahead,
+    maxstall:    maxstall,
+    statsfile:   statsfile,
     }
 }
 
@@ -157,52 +257,170 @@ fn workfile_name(config: &Config, threadno: i32) -> String {
   path
 }
 
+/// A source of frame data for `play` to drain from. `Buffered` is the default,
+/// backed by a reader thread and a channel; the `mmap` feature adds
+/// `MmapSource`, which maps the work file and advances an offset instead.
+trait Source {
+  /// Consume `amount` bytes from the source before `deadline`. Returns
+  /// `Ok(eof)` if the amount was consumed (true if doing so hit EOF), or
+  /// `Err(())` if `deadline` passed before enough data arrived.
+  fn consume(&mut self, amount: usize, deadline: &SteadyTime) -> Result<bool, ()>;
+}
+
 struct Buffered {
   local: usize,
   chan:  Receiver<usize>,
 }
 
-/// Simulate playing a video. This will run through the work file at the
-/// configured framerate and frame size, logging every time that a frame could
-/// not be delivered on time. The final result of this function is a message
-/// that displays the total number of frames that were "played", and how many
-/// had to be dropped.
-fn play(config: &Config, threadno: i32) {
+impl Source for Buffered {
+  fn consume(&mut self, amount: usize, deadline: &SteadyTime) -> Result<bool, ()> {
+    read_buffer(self, amount, deadline)
+  }
+}
+
+/// Simulate playing a video against a chunked look-ahead buffer: a
+/// background fill (see `stream_buffer::spawn_fill`) keeps a playback buffer,
+/// measured in seconds-of-video, topped up to `config.lookahead`, while this
+/// loop drains `1/framerate` seconds from it per frame. Every time the buffer
+/// is already empty when a frame needs it, that's a rebuffering event; if it
+/// stays empty for more than `config.maxstall` consecutive frames the stream
+/// is aborted. Prints a summary and, if `config.statsfile` is set, writes out
+/// per-second min/mean/max latency and buffer occupancy. Returns
+/// `(total, stalls)` so a caller aggregating several threads (or reporting to
+/// a coordinator) doesn't have to re-derive them.
+fn play(config: &Config, threadno: i32) -> (i32, i32) {
   let path            = workfile_name(config, threadno);
   let mut total       = 0;
-  let mut fails       = 0;
+  let mut stalls      = 0;
   let frame_len       = Duration::microseconds( (1e6 / config.framerate) as i64);
+  let frame_secs      = 1.0 / (config.framerate as f64);
   let start           = SteadyTime::now();
   let end_time        = start + config.timelimit;
   let mut frame_end   = start + frame_len;
-  let (tx, rx)        = sync_channel(8);
-  let mut buffered    = Buffered { local: 0, chan: rx };
+  let (source, frame_sz) = match config.capture {
+    Some(ref dev) => {
+      let (capture, sz) = capture_source::open(dev);
+      (Box::new(capture) as Box<Source>, sz)
+    }
+    None => (make_source(config, path), config.framesize)
+  };
 
-  thread::spawn(move || { read_file(tx, path) });
+  let buffer        = stream_buffer::spawn_fill(source, frame_sz, frame_secs, config.lookahead);
+  let mut jitter    = stream_buffer::JitterLog::new(start);
+  let mut empty_run = 0;
 
   loop {
     total += 1;
-    if frame(&mut buffered, config.framesize, &frame_end, &mut fails) {
-      report(total, fails);
-      return;
+
+    let (had_video, occupancy, starved) = {
+      let mut st = buffer.lock().unwrap();
+      if st.buffered_secs >= frame_secs {
+        st.buffered_secs -= frame_secs;
+        (true, st.buffered_secs, false)
+      } else {
+        let starved = st.eof && st.buffered_secs <= 0.0;
+        st.buffered_secs = 0.0;
+        (false, 0.0, starved)
+      }
+    };
+
+    if had_video {
+      empty_run = 0;
+    } else {
+      stalls    += 1;
+      empty_run += 1;
+      println!("rebuffering: playback buffer is empty");
+    }
+
+    let now        = SteadyTime::now();
+    let latency_ms = (now - frame_end).num_microseconds().unwrap_or(0) as f64 / 1000.0;
+    jitter.record(now, latency_ms, occupancy);
+
+    if empty_run > config.maxstall {
+      println!("buffer underrun for {} consecutive frames; aborting stream", empty_run);
+      finish_stats(config, threadno, jitter);
+      report(total, stalls);
+      return (total, stalls);
+    }
+    if starved {
+      finish_stats(config, threadno, jitter);
+      report(total, stalls);
+      return (total, stalls);
     }
     if SteadyTime::now() > end_time {
-      report(total, fails);
-      return;
+      finish_stats(config, threadno, jitter);
+      report(total, stalls);
+      return (total, stalls);
     }
+
     frame_end   = frame_end + frame_len;
+    let delay   = (frame_end - SteadyTime::now()).num_milliseconds();
+    if delay > 0 {
+      sleep_ms(delay as u32);
+    }
+  }
+}
+
+/// Flush the accumulated per-second jitter samples to `config.statsfile`, if
+/// one was given. Each thread gets its own file, namespaced by `threadno`
+/// the same way `workfile_name` namespaces work files, since every `play`
+/// thread runs its own `JitterLog` and a shared path would just have
+/// threads truncate and overwrite each other's output.
+fn finish_stats(config: &Config, threadno: i32, jitter: stream_buffer::JitterLog) {
+  if let Some(ref path) = config.statsfile {
+    stream_buffer::write_stats(&stats_file_name(path, threadno), &jitter.finish());
+  }
+}
+
+/// Insert `-<threadno>` before the file extension (or at the end, if there
+/// isn't one) so concurrent `play` threads don't clobber each other's stats.
+/// Only looks for the extension dot in the filename itself, not the whole
+/// path, so a `.` in a directory component doesn't get mistaken for one.
+fn stats_file_name(path: &str, threadno: i32) -> String {
+  let basename_start = match path.rfind('/') {
+    Some(idx) => idx + 1,
+    None      => 0,
+  };
+  match path[basename_start..].rfind('.') {
+    Some(rel_idx) => {
+      let idx = basename_start + rel_idx;
+      format!("{}-{}{}", &path[..idx], threadno, &path[idx..])
+    }
+    None => format!("{}-{}", path, threadno),
   }
 }
 
-fn report(total: i32, fails: i32) {
-  let percent = 100.0 * (fails as f32) / (total as f32);
-  println!("{} frames, {} failures ({}%)", total, fails, percent);
+/// Build the `Source` that `play` will drain frame data from: the default
+/// reader-thread/channel pipeline, or an mmap-backed source when `--mmap` is
+/// given and the `mmap` feature is compiled in.
+fn make_source(config: &Config, path: String) -> Box<Source> {
+  if config.mmap {
+    return mmap_for(path);
+  }
+  let (tx, rx) = bounded(8);
+  thread::spawn(move || { read_file(tx, path) });
+  Box::new(Buffered { local: 0, chan: rx })
+}
+
+#[cfg(feature = "mmap")]
+fn mmap_for(path: String) -> Box<Source> {
+  Box::new(mmap_source::MmapSource::open(&path))
+}
+
+#[cfg(not(feature = "mmap"))]
+fn mmap_for(_path: String) -> Box<Source> {
+  panic!("vio was built without the `mmap` feature; rebuild with --features mmap")
+}
+
+fn report(total: i32, stalls: i32) {
+  let percent = 100.0 * (stalls as f32) / (total as f32);
+  println!("{} frames, {} rebuffers ({}%)", total, stalls, percent);
 }
 
 /// Reads the entire file, writing to the channel the amount that it's read.
-/// The frame function will feed from the associated channel when it needs more
-/// data to "play".
-fn read_file(tx: SyncSender<usize>, path: String) {
+/// The background fill thread drains this channel to keep the playback
+/// buffer topped up.
+fn read_file(tx: Sender<usize>, path: String) {
   let mut file         = File::open(path).unwrap();
   let mut buf: Vec<u8> = repeat(0).take(4*1024*1024).collect();
   loop {
@@ -214,49 +432,38 @@ fn read_file(tx: SyncSender<usize>, path: String) {
   }
 }
 
-/// Read the desired amount of data from the buffered file. Returns True if the
-/// file hit EOF, False if there's more to read.
-fn read_buffer(buffered: &mut Buffered, mut amount: usize) -> bool {
+/// Read the desired amount of data from the buffered file, never waiting
+/// past `deadline` for it. Returns `Ok(eof)` if the file hit EOF while doing
+/// so, or `Err(())` if `deadline` passed first.
+///
+/// The recursive "consume local bytes, then pull a chunk" structure stays the
+/// same as before, but each recursion recomputes the remaining budget from
+/// `deadline` and hands it to `recv_timeout`, so a reader thread that falls
+/// behind makes this return at the deadline instead of blocking past it and
+/// inflating every later frame's deadline in turn. This only gives accurate,
+/// non-cascading jitter measurements under sustained I/O starvation as long
+/// as the caller passes a `deadline` that's actually tight to what it needs
+/// the data for; `stream_buffer::spawn_fill` derives its deadline from how
+/// much buffered video is left before playback runs dry.
+fn read_buffer(buffered: &mut Buffered, mut amount: usize, deadline: &SteadyTime) -> Result<bool, ()> {
   if buffered.local > amount {
     buffered.local -= amount;
-    false
+    Ok(false)
   }
   else {
     amount -= buffered.local;
-    match buffered.chan.recv() {
-      Ok(more) => { buffered.local = more; read_buffer(buffered, amount) }
-      Err(_)   => { true }
+    let remaining = *deadline - SteadyTime::now();
+    let budget = if remaining.num_milliseconds() > 0 {
+      StdDuration::from_millis(remaining.num_milliseconds() as u64)
+    } else {
+      StdDuration::from_millis(0)
+    };
+    match buffered.chan.recv_timeout(budget) {
+      Ok(more)                             => { buffered.local = more; read_buffer(buffered, amount, deadline) }
+      Err(RecvTimeoutError::Timeout)       => { Err(()) }
+      Err(RecvTimeoutError::Disconnected)  => { Ok(true) }
     }
   }
 }
 
-/// Play a frame. This takes the time at which the frame needs to be completed.
-/// If the function is called after that time (because a previous frame was
-/// seriously delayed) it will fail immediately and log the failure. If this
-/// frame takes too long, the failure will be logged. If the frame gets loaded
-/// before the cutoff time, this function will sleep until the frame is done
-/// being shown.
-///
-/// This returns True if the file reaches EOF, false if there's more to be read.
-fn frame(buffered:  &mut Buffered,
-         frame_sz:  usize,
-         frame_end: &SteadyTime,
-         fails:     &mut i32
-        ) -> bool {
-  if SteadyTime::now() > *frame_end {
-    *fails += 1;
-    return false;
-  }
-
-  let eof = read_buffer(buffered, frame_sz);
-  if SteadyTime::now() > *frame_end {
-    *fails += 1;
-    return eof;
-  }
-  let delay = (*frame_end - SteadyTime::now()).num_milliseconds();
-  if delay > 0 {
-    sleep_ms(delay as u32);
-  }
-  eof
-}
 
@@ -0,0 +1,48 @@
+//! Memory-mapped alternative to the reader-thread/channel `Buffered` source,
+//! gated behind the `mmap` Cargo feature (mirroring how mapcat feature-gates
+//! its threaded path). Instead of draining a bounded channel filled by a
+//! `read_file` thread, this maps the whole work file up front and advances an
+//! offset into it, letting the kernel's page cache and fault handling stand
+//! in for explicit `read()` syscalls.
+
+use memmap::{Mmap, Protection};
+use std::ptr;
+use time::SteadyTime;
+use Source;
+
+pub struct MmapSource {
+  mmap:   Mmap,
+  offset: usize,
+}
+
+impl MmapSource {
+  pub fn open(path: &str) -> MmapSource {
+    let mmap = Mmap::open_path(path, Protection::Read).unwrap();
+    MmapSource { mmap: mmap, offset: 0 }
+  }
+}
+
+impl Source for MmapSource {
+  /// Advance past `amount` bytes of the mapping, touching the first byte of
+  /// every page along the way so the kernel actually has to fault it in
+  /// rather than the access being optimized away. Returns `Ok(true)` once the
+  /// mapping is exhausted; page faults aren't bounded by a deadline, so this
+  /// never times out.
+  fn consume(&mut self, amount: usize, _deadline: &SteadyTime) -> Result<bool, ()> {
+    let slice = unsafe { self.mmap.as_slice() };
+    if self.offset >= slice.len() {
+      return Ok(true);
+    }
+
+    let end       = ::std::cmp::min(self.offset + amount, slice.len());
+    let page_size = 4096;
+    let mut i      = self.offset - (self.offset % page_size);
+    while i < end {
+      unsafe { ptr::read_volatile(&slice[i]); }
+      i += page_size;
+    }
+
+    self.offset = end;
+    Ok(self.offset >= slice.len())
+  }
+}
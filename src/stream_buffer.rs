@@ -0,0 +1,176 @@
+//! Chunked look-ahead buffer model, replacing the old single-frame pass/fail
+//! counter with something closer to a real streaming player (in the spirit
+//! of chunked transcoders like nightfall): a background fill keeps a
+//! playback buffer topped up to a `--lookahead` target measured in
+//! seconds-of-video, `play` drains `1/framerate` seconds from it per frame,
+//! and a watchdog aborts the stream if the buffer stays empty too long.
+//! Also collects per-wall-clock-interval latency/occupancy samples so a run
+//! can be graphed instead of collapsed into one percentage.
+
+use time::{Duration, SteadyTime};
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::sleep_ms;
+use Source;
+
+/// Floor for the `recv_timeout` budget `spawn_fill` hands to `consume`, so a
+/// starved buffer (`buffered_secs == 0.0`) still waits a few milliseconds per
+/// attempt instead of busy-spinning `recv_timeout(0)`.
+const MIN_BUDGET_MS: i64 = 5;
+
+/// Shared between the background fill thread and the playback loop: how many
+/// seconds of video are currently buffered, and whether the source has hit
+/// EOF (the fill thread stops producing once it has).
+pub struct BufferState {
+  pub buffered_secs: f64,
+  pub eof:           bool,
+}
+
+/// Spawn a background thread that reads `source` one `frame_sz`-byte chunk
+/// (`frame_secs` worth of video) at a time, keeping the shared buffer topped
+/// up to `lookahead` seconds ahead of playback.
+pub fn spawn_fill(mut source: Box<Source>,
+                   frame_sz:   usize,
+                   frame_secs: f64,
+                   lookahead:  f64
+                  ) -> Arc<Mutex<BufferState>> {
+  let state = Arc::new(Mutex::new(BufferState { buffered_secs: 0.0, eof: false }));
+  let bg    = state.clone();
+
+  thread::spawn(move || {
+    loop {
+      let buffered_secs = {
+        let st = bg.lock().unwrap();
+        if st.eof {
+          return;
+        }
+        st.buffered_secs
+      };
+      if buffered_secs >= lookahead {
+        sleep_ms(10);
+        continue;
+      }
+
+      // Give `consume` only as much time as the buffer already has banked:
+      // that's exactly how long playback can run before it would catch up
+      // and stall, so a reader that can't keep up trips `Err(())` here
+      // instead of blocking the fill thread (and the watchdog behind it)
+      // indefinitely. Floor it at MIN_BUDGET_MS so a starved buffer (the
+      // condition this whole feature exists to measure) doesn't collapse
+      // the budget to 0ms and turn this into a `recv_timeout(0)` busy-spin
+      // that steals CPU and lock time from the `play` thread.
+      let budget_ms = ((buffered_secs * 1000.0) as i64).max(MIN_BUDGET_MS);
+      let deadline  = SteadyTime::now() + Duration::milliseconds(budget_ms);
+
+      match source.consume(frame_sz, &deadline) {
+        Ok(eof) => {
+          let mut st = bg.lock().unwrap();
+          st.buffered_secs += frame_secs;
+          if eof {
+            st.eof = true;
+          }
+        }
+        Err(()) => {
+          // Timed out before a full chunk arrived; don't credit the buffer
+          // for video that isn't actually there yet. The recv_timeout above
+          // already waited out the budget (at least MIN_BUDGET_MS), so just
+          // retry.
+        }
+      }
+    }
+  });
+
+  state
+}
+
+/// One fixed wall-clock interval's worth of latency/occupancy stats.
+pub struct IntervalSample {
+  pub interval_sec:         i64,
+  pub min_latency_ms:       f64,
+  pub mean_latency_ms:      f64,
+  pub max_latency_ms:       f64,
+  pub buffer_occupancy_sec: f64,
+}
+
+/// Rolls per-frame latency samples up into 1-second wall-clock buckets.
+pub struct JitterLog {
+  start:          SteadyTime,
+  bucket:         i64,
+  latencies_ms:   Vec<f64>,
+  last_occupancy: f64,
+  samples:        Vec<IntervalSample>,
+}
+
+impl JitterLog {
+  pub fn new(start: SteadyTime) -> JitterLog {
+    JitterLog {
+      start:          start,
+      bucket:         0,
+      latencies_ms:   Vec::new(),
+      last_occupancy: 0.0,
+      samples:        Vec::new(),
+    }
+  }
+
+  /// Record one frame's latency (how far `now` is past its scheduled
+  /// deadline, in milliseconds) and the buffer occupancy at that point.
+  pub fn record(&mut self, now: SteadyTime, latency_ms: f64, occupancy_sec: f64) {
+    let bucket = (now - self.start).num_seconds();
+    if bucket != self.bucket {
+      self.flush_bucket();
+      self.bucket = bucket;
+    }
+    self.latencies_ms.push(latency_ms);
+    self.last_occupancy = occupancy_sec;
+  }
+
+  fn flush_bucket(&mut self) {
+    if self.latencies_ms.is_empty() {
+      return;
+    }
+    let min  = self.latencies_ms.iter().cloned().fold(std::f64::INFINITY, f64::min);
+    let max  = self.latencies_ms.iter().cloned().fold(std::f64::NEG_INFINITY, f64::max);
+    let mean = self.latencies_ms.iter().sum::<f64>() / (self.latencies_ms.len() as f64);
+    self.samples.push(IntervalSample {
+      interval_sec:         self.bucket,
+      min_latency_ms:       min,
+      mean_latency_ms:      mean,
+      max_latency_ms:       max,
+      buffer_occupancy_sec: self.last_occupancy,
+    });
+    self.latencies_ms.clear();
+  }
+
+  /// Flush the final (possibly partial) bucket and return all samples.
+  pub fn finish(mut self) -> Vec<IntervalSample> {
+    self.flush_bucket();
+    self.samples
+  }
+}
+
+/// Write `samples` to `path` as CSV, or as JSON when `path` ends in `.json`.
+pub fn write_stats(path: &str, samples: &[IntervalSample]) {
+  let mut fd = File::create(path).unwrap();
+  if path.ends_with(".json") {
+    let mut out = String::from("[\n");
+    for (i, s) in samples.iter().enumerate() {
+      if i > 0 {
+        out.push_str(",\n");
+      }
+      out.push_str(&format!(
+        "  {{\"interval\": {}, \"min_latency_ms\": {:.3}, \"mean_latency_ms\": {:.3}, \"max_latency_ms\": {:.3}, \"buffer_occupancy_sec\": {:.3}}}",
+        s.interval_sec, s.min_latency_ms, s.mean_latency_ms, s.max_latency_ms, s.buffer_occupancy_sec));
+    }
+    out.push_str("\n]\n");
+    fd.write_all(out.as_bytes()).unwrap();
+  } else {
+    let mut out = String::from("interval,min_latency_ms,mean_latency_ms,max_latency_ms,buffer_occupancy_sec\n");
+    for s in samples {
+      out.push_str(&format!("{},{:.3},{:.3},{:.3},{:.3}\n",
+        s.interval_sec, s.min_latency_ms, s.mean_latency_ms, s.max_latency_ms, s.buffer_occupancy_sec));
+    }
+    fd.write_all(out.as_bytes()).unwrap();
+  }
+}